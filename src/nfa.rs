@@ -36,6 +36,125 @@ lazy_static! {
 enum Symbol {
     LeftParen,
     Or,
+    Concat,
+}
+
+impl Symbol {
+    /// Higher binds tighter: `|` < `.` (concatenation). `*`/`+`/`?` never sit
+    /// on the operator stack since they are unary and applied immediately.
+    fn precedence(&self) -> u8 {
+        match self {
+            Symbol::LeftParen => 0,
+            Symbol::Or => 1,
+            Symbol::Concat => 2,
+        }
+    }
+}
+
+/// Sentinel inserted between adjacent atoms during preprocessing to make the
+/// otherwise-implicit concatenation operator explicit for the shunting-yard
+/// pass. Never appears in a user-supplied pattern.
+const CONCAT_OP: char = '\u{1}';
+
+/// Whether `c` can end an atom/sub-expression, i.e. something can be
+/// concatenated onto it.
+fn ends_atom(c: char) -> bool {
+    !matches!(c, '(' | '|')
+}
+
+/// Whether `c` can start a new atom/sub-expression.
+fn starts_atom(c: char) -> bool {
+    !matches!(c, ')' | '|' | '*' | '+' | '?')
+}
+
+/// Whether `c` is one of the operators `new_from_token` gives meaning to, and
+/// so must be backslash-escaped to appear as a literal (used when expanding
+/// `[...]` classes, which may contain these as ordinary members).
+fn is_operator_char(c: char) -> bool {
+    matches!(c, '(' | ')' | '|' | '*' | '+' | '?' | '\\')
+}
+
+/// Inserts [`CONCAT_OP`] between adjacent atoms, e.g. `ab` -> `a<CONCAT_OP>b`,
+/// so concatenation can be handled by the shunting-yard pass like any other
+/// binary operator. A `\x` escape pair is treated as a single literal atom.
+fn insert_concat_ops(pattern: &str) -> String {
+    let mut result = String::new();
+    let mut prev_ends_atom = false;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        let is_escape = c == '\\' && chars.peek().is_some();
+        let starts = if is_escape { true } else { starts_atom(c) };
+        if prev_ends_atom && starts {
+            result.push(CONCAT_OP);
+        }
+        result.push(c);
+        if is_escape {
+            result.push(chars.next().unwrap());
+            prev_ends_atom = true;
+        } else {
+            prev_ends_atom = ends_atom(c);
+        }
+    }
+    result
+}
+
+/// Expands a `[...]` bracket expression's contents (without the surrounding
+/// brackets) into an explicit parenthesized alternation of its members, e.g.
+/// `0-9` -> `(0|1|...|9)`. Supports `a-z` ranges and `\x`-escaped members, so
+/// a class can contain characters that `new_from_token` treats as operators
+/// (e.g. `\-`, `\+`).
+fn expand_char_class(class: &str) -> String {
+    let chars: Vec<char> = class.chars().collect();
+    let mut members = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '\\' && i + 1 < chars.len() {
+            members.push(chars[i + 1]);
+            i += 2;
+        } else if i + 2 < chars.len() && chars[i + 1] == '-' {
+            members.extend(chars[i]..=chars[i + 2]);
+            i += 3;
+        } else {
+            members.push(chars[i]);
+            i += 1;
+        }
+    }
+    let mut result = String::from("(");
+    for (idx, c) in members.into_iter().enumerate() {
+        if idx > 0 {
+            result.push('|');
+        }
+        if is_operator_char(c) {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result.push(')');
+    result
+}
+
+/// Replaces every `[...]` bracket expression in `pattern` with its
+/// [`expand_char_class`] expansion, so the rest of `new_from_token` never has
+/// to know character classes exist.
+fn expand_char_classes(pattern: &str) -> String {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '[' {
+            let close = chars[i..]
+                .iter()
+                .position(|&c| c == ']')
+                .map(|offset| i + offset)
+                .expect("unterminated character class");
+            result.push_str(&expand_char_class(&chars[i + 1..close].iter().collect::<String>()));
+            i = close + 1;
+        } else {
+            result.push(chars[i]);
+            i += 1;
+        }
+    }
+    result
 }
 
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -172,63 +291,170 @@ impl NFA {
         self.states.extend(other.states);
     }
 
+    // single-char fragment: initial -c-> accept
+    fn from_char(c: char) -> Self {
+        let mut nfa = NFA::new();
+        let accept = nfa.add_state(true);
+        nfa.initial
+            .inner
+            .borrow_mut()
+            .add_transition(Transition::Char(c), accept);
+        nfa
+    }
+
+    // a* : new initial epsilon-branches to the old fragment and to a shared
+    // accept (zero occurrences), and the old accepts loop back epsilon-wise
+    // to the new initial (repetition).
+    fn apply_star(&mut self) {
+        let old_accepting: Vec<Arc<State>> = self
+            .states
+            .iter()
+            .filter(|state| state.inner.borrow().accepting)
+            .cloned()
+            .collect();
+        let new_initial = self.add_state(false);
+        let accept = self.add_state(true);
+        new_initial
+            .inner
+            .borrow_mut()
+            .add_transition(Transition::Epsilon, self.initial.clone());
+        new_initial
+            .inner
+            .borrow_mut()
+            .add_transition(Transition::Epsilon, accept);
+        for state in old_accepting {
+            let mut inner = state.inner.borrow_mut();
+            inner.accepting = false;
+            inner.add_transition(Transition::Epsilon, new_initial.clone());
+        }
+        self.initial = new_initial;
+    }
+
+    // a+ : reuse the fragment as-is, just add a back epsilon edge from each
+    // accept to the start so it can repeat.
+    fn apply_plus(&mut self) {
+        let accepting: Vec<Arc<State>> = self
+            .states
+            .iter()
+            .filter(|state| state.inner.borrow().accepting)
+            .cloned()
+            .collect();
+        for state in accepting {
+            state
+                .inner
+                .borrow_mut()
+                .add_transition(Transition::Epsilon, self.initial.clone());
+        }
+    }
+
+    // a? : new initial epsilon-branches to the old fragment and to a shared
+    // accept (bypass), old accepts funnel into that shared accept.
+    fn apply_question(&mut self) {
+        let old_accepting: Vec<Arc<State>> = self
+            .states
+            .iter()
+            .filter(|state| state.inner.borrow().accepting)
+            .cloned()
+            .collect();
+        let new_initial = self.add_state(false);
+        let accept = self.add_state(true);
+        new_initial
+            .inner
+            .borrow_mut()
+            .add_transition(Transition::Epsilon, self.initial.clone());
+        new_initial
+            .inner
+            .borrow_mut()
+            .add_transition(Transition::Epsilon, accept.clone());
+        for state in old_accepting {
+            let mut inner = state.inner.borrow_mut();
+            inner.accepting = false;
+            inner.add_transition(Transition::Epsilon, accept.clone());
+        }
+        self.initial = new_initial;
+    }
+
+    // pops the top (binary) operator and applies it to the top two fragments
+    // on the stack, pushing the result back
+    fn apply_operator(symbol_stack: &mut Vec<Symbol>, nfa_stack: &mut Vec<NFA>) {
+        let symbol = symbol_stack.pop().unwrap();
+        let right = nfa_stack.pop().unwrap();
+        let mut left = nfa_stack.pop().unwrap();
+        match symbol {
+            // nfa -> left
+            //     -> right
+            Symbol::Or => left.merge_other(right),
+            // left -> right
+            Symbol::Concat => left.connect_other(right),
+            Symbol::LeftParen => unreachable!("LeftParen is never pushed as an applied operator"),
+        }
+        nfa_stack.push(left);
+    }
+
     #[allow(dead_code)]
     pub fn new_from_token(token: &Token) -> Self {
-        let token = format!("({})", &token.value);
+        let pattern = expand_char_classes(&format!("({})", &token.value));
+        let pattern = insert_concat_ops(&pattern);
         let mut symbol_stack: Vec<Symbol> = Vec::new();
         let mut nfa_stack: Vec<NFA> = Vec::new();
-        let handle_symbol = |symbol_stack: &mut Vec<Symbol>, nfa_stack: &mut Vec<NFA>| {
-            let symbol = symbol_stack.pop().unwrap();
-            let mut nfa1 = nfa_stack.pop().unwrap();
-            let nfa2 = nfa_stack.pop().unwrap();
-            match symbol {
-                Symbol::Or => {
-                    // nfa1 | nfa2
-                    // nfa -> nfa1
-                    //     -> nfa2
-                    nfa1.merge_other(nfa2);
-                    nfa_stack.push(nfa1);
-                }
-                _ => {}
-            }
+        // nfa_stack.len() recorded at the start of the current alternation
+        // branch (reset at each '(' and each '|' at that nesting level), so
+        // a branch that produced no fragment (an empty group `()`, or an
+        // empty `|` alternative as in `a|`, `|a`, `a||b`) can have a
+        // placeholder matching only the empty string supplied instead of
+        // underflowing nfa_stack.
+        let mut branch_marks: Vec<usize> = Vec::new();
+        let empty_fragment = || {
+            let nfa = NFA::new();
+            nfa.initial.inner.borrow_mut().accepting = true;
+            nfa
         };
-        for (idx, c) in token.chars().enumerate() {
+
+        let mut chars = pattern.chars().peekable();
+        while let Some(c) = chars.next() {
             match c {
                 '(' => {
                     symbol_stack.push(Symbol::LeftParen);
-                    nfa_stack.push(NFA::new());
+                    branch_marks.push(nfa_stack.len());
                 }
                 ')' => {
+                    if nfa_stack.len() == *branch_marks.last().unwrap() {
+                        nfa_stack.push(empty_fragment());
+                    }
                     // while the top of the symbol stack is not '('
                     while *symbol_stack.last().unwrap() != Symbol::LeftParen {
-                        handle_symbol(&mut symbol_stack, &mut nfa_stack);
+                        Self::apply_operator(&mut symbol_stack, &mut nfa_stack);
                     }
                     symbol_stack.pop();
+                    branch_marks.pop();
                 }
-                '|' => {
-                    symbol_stack.push(Symbol::Or);
-                    nfa_stack.push(NFA::new());
+                '|' | CONCAT_OP => {
+                    let is_or = c == '|';
+                    let symbol = if is_or { Symbol::Or } else { Symbol::Concat };
+                    if is_or && nfa_stack.len() == *branch_marks.last().unwrap() {
+                        nfa_stack.push(empty_fragment());
+                    }
+                    while symbol_stack.last().is_some_and(|top| {
+                        *top != Symbol::LeftParen && top.precedence() >= symbol.precedence()
+                    }) {
+                        Self::apply_operator(&mut symbol_stack, &mut nfa_stack);
+                    }
+                    symbol_stack.push(symbol);
+                    if is_or {
+                        *branch_marks.last_mut().unwrap() = nfa_stack.len();
+                    }
                 }
-                _ => {
-                    let nfa = nfa_stack.last_mut().unwrap();
-                    // last -epsilon-> state1 -c-> state2
-                    let last_state = nfa.states.last_mut().unwrap().clone();
-                    let state1 = nfa.add_state(false);
-                    // if next char is ) or |, state2 is accepting
-                    let next_c = token.chars().nth(idx + 1).unwrap();
-                    let state2 = nfa.add_state(next_c == '|' || next_c == ')');
-
-                    last_state
-                        .inner
-                        .borrow_mut()
-                        .add_transition(Transition::Epsilon, state1.clone());
-                    state1
-                        .inner
-                        .borrow_mut()
-                        .add_transition(Transition::Char(c), state2.clone());
+                '*' => nfa_stack.last_mut().unwrap().apply_star(),
+                '+' => nfa_stack.last_mut().unwrap().apply_plus(),
+                '?' => nfa_stack.last_mut().unwrap().apply_question(),
+                // \x : literal x, bypassing whatever meaning x would otherwise have
+                '\\' if chars.peek().is_some() => {
+                    nfa_stack.push(NFA::from_char(chars.next().unwrap()))
                 }
+                _ => nfa_stack.push(NFA::from_char(c)),
             }
         }
+
         nfa_stack.pop().unwrap()
     }
 }
@@ -249,4 +475,100 @@ mod tests {
             print!("{}", state.inner.borrow());
         }
     }
+
+    #[test]
+    fn test_nfa_empty_pattern() {
+        // an empty token value wraps to "()", which must still resolve to a
+        // trivial fragment rather than panicking on a drained nfa_stack
+        let nfa = NFA::new_from_token(&Token {
+            value: "".to_string(),
+            kind: "char".to_string(),
+        });
+        assert_eq!(nfa.states.len(), 1);
+    }
+
+    #[test]
+    fn test_nfa_operators() {
+        // star, plus and optional, each binding tighter than concatenation
+        for value in ["[0-9]+", "[a-zA-Z0-9_]*", "ab?c"] {
+            let nfa = NFA::new_from_token(&Token {
+                value: value.to_string(),
+                kind: "char".to_string(),
+            });
+            for state in nfa.states.iter() {
+                print!("{}", state.inner.borrow());
+            }
+        }
+    }
+
+    #[test]
+    fn test_nfa_empty_alternation_branch() {
+        // a trailing, leading or middle empty `|` branch (e.g. an optional
+        // alternative) gets an empty-string placeholder fragment rather than
+        // underflowing nfa_stack and panicking
+        for value in ["a|", "|a", "a||b"] {
+            let nfa = NFA::new_from_token(&Token {
+                value: value.to_string(),
+                kind: "char".to_string(),
+            });
+            for state in nfa.states.iter() {
+                print!("{}", state.inner.borrow());
+            }
+        }
+    }
+
+    fn char_transitions(nfa: &NFA) -> HashSet<char> {
+        nfa.states
+            .iter()
+            .flat_map(|state| {
+                state
+                    .inner
+                    .borrow()
+                    .transitions
+                    .iter()
+                    .filter_map(|(trans, _)| match trans {
+                        Transition::Char(c) => Some(*c),
+                        Transition::Epsilon => None,
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_nfa_char_class() {
+        let digits = NFA::new_from_token(&Token {
+            value: "[0-9]+".to_string(),
+            kind: "INT".to_string(),
+        });
+        let digit_chars = char_transitions(&digits);
+        assert_eq!(digit_chars, ('0'..='9').collect());
+
+        let ident = NFA::new_from_token(&Token {
+            value: "[a-zA-Z0-9_]*".to_string(),
+            kind: "ID".to_string(),
+        });
+        let ident_chars = char_transitions(&ident);
+        assert!(ident_chars.contains(&'a') && ident_chars.contains(&'Z') && ident_chars.contains(&'_'));
+        assert!(!ident_chars.contains(&'[') && !ident_chars.contains(&']') && !ident_chars.contains(&'-'));
+
+        let op = NFA::new_from_token(&Token {
+            value: "[+\\-*/]".to_string(),
+            kind: "OP".to_string(),
+        });
+        assert_eq!(char_transitions(&op), HashSet::from(['+', '-', '*', '/']));
+    }
+
+    #[test]
+    fn test_nfa_nested_groups() {
+        // concatenation across a nested group used to be mishandled by the
+        // old "next char is | or )" heuristic
+        let nfa = NFA::new_from_token(&Token {
+            value: "a(b|c)d".to_string(),
+            kind: "char".to_string(),
+        });
+        for state in nfa.states.iter() {
+            print!("{}", state.inner.borrow());
+        }
+    }
 }